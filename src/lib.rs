@@ -1,12 +1,13 @@
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Version};
 use ripemd::Ripemd320;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use sha2::{Digest, Sha256, Sha512};
 
 pub use argon2::Params as Argon2Params;
 pub use scrypt::Params as ScryptParams;
 
 /// Enum defining different Proof of Work (PoW) algorithms.
+#[allow(non_camel_case_types)]
 pub enum PoWAlgorithm {
     Sha2_256,
     Sha2_512,
@@ -17,7 +18,7 @@ pub enum PoWAlgorithm {
 
 impl PoWAlgorithm {
     /// Calculates SHA-256 hash with given data and nonce.
-    pub fn calculate_sha2_256(data: &[u8], nonce: usize) -> Vec<u8> {
+    pub fn calculate_sha2_256(data: &[u8], nonce: u64) -> Vec<u8> {
         let mut hasher = Sha256::new();
         hasher.update(data);
 
@@ -29,7 +30,7 @@ impl PoWAlgorithm {
     }
 
     /// Calculates SHA-512 hash with given data and nonce.
-    pub fn calculate_sha2_512(data: &[u8], nonce: usize) -> Vec<u8> {
+    pub fn calculate_sha2_512(data: &[u8], nonce: u64) -> Vec<u8> {
         let mut hasher = Sha512::new();
         hasher.update(data);
 
@@ -41,7 +42,7 @@ impl PoWAlgorithm {
     }
 
     /// Calculates RIPEMD320 hash with given data and nonce.
-    pub fn calculate_ripemd_320(data: &[u8], nonce: usize) -> Vec<u8> {
+    pub fn calculate_ripemd_320(data: &[u8], nonce: u64) -> Vec<u8> {
         let mut hasher = Ripemd320::new();
         hasher.update(data);
 
@@ -53,30 +54,42 @@ impl PoWAlgorithm {
     }
 
     /// Calculates Argon2id hash with given data and nonce.
-    pub fn calculate_scrypt(data: &[u8], nonce: usize, params: &ScryptParams) -> Vec<u8> {
+    pub fn calculate_scrypt(data: &[u8], nonce: u64, params: &ScryptParams) -> Vec<u8> {
         let mut output = vec![0; 32];
 
-        scrypt::scrypt(data, &nonce.to_le_bytes(), params, &mut output);
+        scrypt::scrypt(data, &nonce.to_le_bytes(), params, &mut output)
+            .expect("a 32-byte output is always a valid scrypt output length");
 
         output
     }
 
     /// Calculates Scrypt hash with given data and nonce.
-    pub fn calculate_argon2id(data: &[u8], nonce: usize, params: &Argon2Params) -> Vec<u8> {
-        let mut output = vec![0; 32];
-        Argon2::default()
+    pub fn calculate_argon2id(data: &[u8], nonce: u64, params: &Argon2Params) -> Vec<u8> {
+        let mut output = vec![0; params.output_len().unwrap_or(32)];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
             .hash_password_into(data, &nonce.to_le_bytes(), &mut output)
-            .unwrap();
+            .expect("the output buffer is sized from the params, so Argon2 accepts it");
 
         output
     }
 
+    /// Returns the length in bytes of the hash produced by the selected algorithm.
+    pub fn output_len(&self) -> usize {
+        match self {
+            Self::Sha2_256 => 32,
+            Self::Sha2_512 => 64,
+            Self::RIPEMD_320 => 40,
+            Self::Scrypt(_) => 32,
+            Self::Argon2id(params) => params.output_len().unwrap_or(32),
+        }
+    }
+
     /// Calculates hash based on the selected algorithm.
-    pub fn calculate(&self, data: &[u8], nonce: usize) -> Vec<u8> {
+    pub fn calculate(&self, data: &[u8], nonce: u64) -> Vec<u8> {
         match self {
             Self::Sha2_256 => Self::calculate_sha2_256(data, nonce),
             Self::Sha2_512 => Self::calculate_sha2_512(data, nonce),
-            Self::RIPEMD_320 => Self::calculate_sha2_512(data, nonce),
+            Self::RIPEMD_320 => Self::calculate_ripemd_320(data, nonce),
             Self::Scrypt(params) => Self::calculate_scrypt(data, nonce, params),
             Self::Argon2id(params) => Self::calculate_argon2id(data, nonce, params),
         }
@@ -97,6 +110,14 @@ impl PoW {
         difficulty: usize,
         algorithm: PoWAlgorithm,
     ) -> Result<Self, String> {
+        if difficulty > algorithm.output_len() {
+            return Err(format!(
+                "difficulty {} exceeds the {}-byte hash length of the algorithm",
+                difficulty,
+                algorithm.output_len()
+            ));
+        }
+
         Ok(PoW {
             data: serde_json::to_vec(&data).unwrap(),
             difficulty,
@@ -104,39 +125,151 @@ impl PoW {
         })
     }
 
+    /// Creates a new instance of PoW like [`PoW::new`], rejecting configurations whose
+    /// expected number of hashes exceeds `max_expected_attempts`.
+    pub fn new_with_max_expected_attempts(
+        data: impl Serialize,
+        difficulty: usize,
+        algorithm: PoWAlgorithm,
+        max_expected_attempts: f64,
+    ) -> Result<Self, String> {
+        if !max_expected_attempts.is_finite() || max_expected_attempts < 0.0 {
+            return Err(format!(
+                "max expected attempts must be finite and non-negative, got {}",
+                max_expected_attempts
+            ));
+        }
+
+        let pow = Self::new(data, difficulty, algorithm)?;
+        let expected_attempts = pow.expected_attempts();
+
+        if expected_attempts > max_expected_attempts {
+            return Err(format!(
+                "difficulty {} needs {:e} expected attempts, above the ceiling of {:e}",
+                difficulty, expected_attempts, max_expected_attempts
+            ));
+        }
+
+        Ok(pow)
+    }
+
     /// Calculates the target of zeros based on the difficulty
     pub fn calculate_target(&self) -> Vec<u8> {
         // 0x30 is code for ascii character '0'
         vec![0x30u8; self.difficulty]
     }
 
+    /// Returns the expected number of hashes needed to meet the difficulty.
+    pub fn expected_attempts(&self) -> f64 {
+        256f64.powi(self.difficulty as i32)
+    }
+
     /// Calculates PoW with the given target hash.
-    pub fn calculate_pow(&self, target: &[u8]) -> (Vec<u8>, usize) {
-        let mut nonce = 0;
+    ///
+    /// Panics if the target is longer than the hash or every nonce is tried without
+    /// meeting it; use [`PoW::try_calculate_pow`] to handle these as errors.
+    pub fn calculate_pow(&self, target: &[u8]) -> (Vec<u8>, u64) {
+        self.try_calculate_pow(target)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Calculates PoW with the given target hash, returning an error if the target is
+    /// longer than the hash or every nonce is tried without meeting it.
+    pub fn try_calculate_pow(&self, target: &[u8]) -> Result<(Vec<u8>, u64), String> {
+        self.calculate_pow_from(target, 0)
+    }
+
+    /// Calculates PoW with the given target hash, scanning nonces upwards from a random start.
+    ///
+    /// The scan wraps around past the largest nonce. Returns an error if the target is longer
+    /// than the hash or every nonce is tried without meeting it.
+    pub fn calculate_pow_random_start(&self, target: &[u8]) -> Result<(Vec<u8>, u64), String> {
+        self.scan_pow(target, rand::random(), true)
+    }
+
+    /// Calculates PoW with the given target hash, scanning nonces upwards from `start_nonce`.
+    ///
+    /// Returns an error if the target is longer than the hash or the largest nonce
+    /// is reached without meeting the target.
+    pub fn calculate_pow_from(
+        &self,
+        target: &[u8],
+        start_nonce: u64,
+    ) -> Result<(Vec<u8>, u64), String> {
+        self.scan_pow(target, start_nonce, false)
+    }
+
+    /// Scans nonces upwards from `start_nonce` until the target is met. With `wrap_around`
+    /// the scan continues from 0 after the largest nonce and stops once it is back at the start.
+    fn scan_pow(
+        &self,
+        target: &[u8],
+        start_nonce: u64,
+        wrap_around: bool,
+    ) -> Result<(Vec<u8>, u64), String> {
+        if target.len() > self.algorithm.output_len() {
+            return Err(format!(
+                "target of {} bytes exceeds the {}-byte hash length of the algorithm",
+                target.len(),
+                self.algorithm.output_len()
+            ));
+        }
+
+        let mut nonce = start_nonce;
 
         loop {
             let hash = self.algorithm.calculate(&self.data, nonce);
 
-            if &hash[..target.len()] == target {
-                return (hash, nonce);
+            if hash.starts_with(target) {
+                return Ok((hash, nonce));
+            }
+
+            nonce = if wrap_around {
+                nonce.wrapping_add(1)
+            } else {
+                nonce
+                    .checked_add(1)
+                    .ok_or_else(|| format!("nonce space exhausted after nonce {}", nonce))?
+            };
+
+            if nonce == start_nonce {
+                return Err(format!("nonce space exhausted after nonce {}", nonce));
             }
-            nonce += 1;
         }
     }
 
     /// Verifies PoW with the given target hash and PoW result.
-    pub fn verify_pow(&self, target: &[u8], pow_result: (Vec<u8>, usize)) -> bool {
+    ///
+    /// The comparisons here are deliberately not constant-time: the data, target,
+    /// hash and nonce are all public, so there is nothing for a timing attack to leak.
+    /// Targets longer than the hash are rejected rather than causing a panic.
+    pub fn verify_pow(&self, target: &[u8], pow_result: (Vec<u8>, u64)) -> bool {
         let (hash, nonce) = pow_result;
 
         let calculated_hash = self.algorithm.calculate(&self.data, nonce);
 
-        if &calculated_hash[..target.len()] == target && calculated_hash == hash {
+        if calculated_hash.starts_with(target) && calculated_hash == hash {
             return true;
         }
         false
     }
 }
 
+#[cfg(test)]
+mod compile_checks {
+    use super::*;
+
+    const fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    // Fails to compile if a public type stops being shareable across threads.
+    const _: () = {
+        assert_send_sync::<PoW>();
+        assert_send_sync::<PoWAlgorithm>();
+        assert_send_sync::<ScryptParams>();
+        assert_send_sync::<Argon2Params>();
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -205,14 +338,26 @@ mod tests {
         let nonce = 12345;
         let params = Argon2Params::new(16, 2, 2, None).unwrap();
         let expected_hash = [
-            121, 222, 173, 128, 44, 161, 236, 9, 56, 163, 21, 161, 111, 241, 182, 60, 144, 77, 206,
-            200, 220, 147, 149, 223, 6, 115, 230, 200, 155, 53, 29, 42,
+            243, 150, 29, 238, 126, 244, 47, 122, 69, 22, 69, 20, 102, 5, 218, 124, 251, 140, 204,
+            53, 133, 2, 147, 207, 66, 17, 241, 177, 20, 249, 251, 155,
         ];
 
         let hash = PoWAlgorithm::calculate_argon2id(data, nonce, &params);
 
         assert_eq!(hash, expected_hash);
     }
+    #[test]
+    fn test_pow_algorithm_argon2id_output_len() {
+        let data = b"hello world";
+        let nonce = 12345;
+        let params = Argon2Params::new(16, 2, 2, Some(64)).unwrap();
+
+        let hash = PoWAlgorithm::calculate_argon2id(data, nonce, &params);
+
+        assert_eq!(hash.len(), 64);
+        assert!(PoW::new(data, 64, PoWAlgorithm::Argon2id(params)).is_ok());
+    }
+
     #[test]
     fn test_pow_calculate_pow() {
         let data = "hello world";
@@ -221,10 +366,190 @@ mod tests {
         let algorithm = PoWAlgorithm::Sha2_512;
         let pow = PoW::new(data, difficulty, algorithm).unwrap();
 
-        let (hash, nonce) = pow.calculate_pow(&target);
+        let (hash, nonce) = pow.calculate_pow(target);
 
         assert!(hash.starts_with(&target[..difficulty]));
 
-        assert!(pow.verify_pow(&target, (hash.clone(), nonce)));
+        assert!(pow.verify_pow(target, (hash.clone(), nonce)));
+    }
+
+    #[test]
+    fn test_pow_calculate_pow_from() {
+        let data = "hello world";
+        let difficulty = 1;
+        let target = "0".as_bytes();
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+
+        let (first_hash, first_nonce) = pow.calculate_pow(target);
+        let (hash, nonce) = pow.calculate_pow_from(target, first_nonce + 1).unwrap();
+
+        assert!(nonce > first_nonce);
+        assert_ne!(hash, first_hash);
+        assert!(pow.verify_pow(target, (hash, nonce)));
+
+        assert_eq!(
+            pow.calculate_pow_from(target, first_nonce),
+            Ok((first_hash, first_nonce))
+        );
+    }
+
+    #[test]
+    fn test_pow_calculate_pow_random_start() {
+        let data = "hello world";
+        let difficulty = 1;
+        let target = "0".as_bytes();
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+
+        let (hash, nonce) = pow.calculate_pow_random_start(target).unwrap();
+
+        assert!(hash.starts_with(target));
+        assert!(pow.verify_pow(target, (hash, nonce)));
+    }
+
+    #[test]
+    fn test_pow_scan_pow_wraps_around() {
+        let data = "hello world";
+        let difficulty = 1;
+        let target = "0".as_bytes();
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+
+        let (first_hash, first_nonce) = pow.calculate_pow(target);
+
+        assert!(pow.calculate_pow_from(target, u64::MAX).is_err());
+        assert_eq!(
+            pow.scan_pow(target, u64::MAX, true),
+            Ok((first_hash, first_nonce))
+        );
+    }
+
+    #[test]
+    fn test_pow_algorithm_output_len_matches_hash() {
+        let data = b"hello world";
+        let nonce = 12345;
+        let algorithms = [
+            PoWAlgorithm::Sha2_256,
+            PoWAlgorithm::Sha2_512,
+            PoWAlgorithm::RIPEMD_320,
+            PoWAlgorithm::Scrypt(ScryptParams::new(8, 4, 1, 32).unwrap()),
+            PoWAlgorithm::Argon2id(Argon2Params::new(16, 2, 2, None).unwrap()),
+            PoWAlgorithm::Argon2id(Argon2Params::new(16, 2, 2, Some(64)).unwrap()),
+        ];
+
+        for algorithm in algorithms {
+            assert_eq!(
+                algorithm.output_len(),
+                algorithm.calculate(data, nonce).len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pow_calculate_pow_from_exhausts_nonce_space() {
+        let data = "hello world";
+        let difficulty = 32;
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+        let target = pow.calculate_target();
+
+        assert_eq!(
+            pow.calculate_pow_from(&target, u64::MAX - 2),
+            Err(format!("nonce space exhausted after nonce {}", u64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_pow_new_rejects_difficulty_above_hash_length() {
+        let data = "hello world";
+
+        assert!(PoW::new(data, 32, PoWAlgorithm::Sha2_256).is_ok());
+        assert!(PoW::new(data, 33, PoWAlgorithm::Sha2_256).is_err());
+        assert!(PoW::new(data, 64, PoWAlgorithm::Sha2_512).is_ok());
+        assert!(PoW::new(data, 65, PoWAlgorithm::Sha2_512).is_err());
+    }
+
+    #[test]
+    fn test_pow_expected_attempts() {
+        let pow = PoW::new("hello world", 2, PoWAlgorithm::Sha2_256).unwrap();
+
+        assert_eq!(pow.expected_attempts(), 65536.0);
+    }
+
+    #[test]
+    fn test_pow_new_with_max_expected_attempts() {
+        let data = "hello world";
+
+        assert!(
+            PoW::new_with_max_expected_attempts(data, 2, PoWAlgorithm::Sha2_256, 65536.0).is_ok()
+        );
+        assert!(
+            PoW::new_with_max_expected_attempts(data, 3, PoWAlgorithm::Sha2_256, 65536.0).is_err()
+        );
+        assert!(
+            PoW::new_with_max_expected_attempts(data, 32, PoWAlgorithm::Sha2_256, 1e12).is_err()
+        );
+    }
+
+    #[test]
+    fn test_pow_new_with_max_expected_attempts_rejects_invalid_ceiling() {
+        let data = "hello world";
+
+        for ceiling in [f64::NAN, f64::INFINITY, -1.0] {
+            assert!(
+                PoW::new_with_max_expected_attempts(data, 1, PoWAlgorithm::Sha2_256, ceiling)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pow_new_with_max_expected_attempts_error_is_compact() {
+        let data = "hello world";
+
+        let result = PoW::new_with_max_expected_attempts(data, 32, PoWAlgorithm::Sha2_256, 1e12);
+
+        assert_eq!(
+            result.err().unwrap(),
+            "difficulty 32 needs 1.157920892373162e77 expected attempts, above the ceiling of 1e12"
+        );
+    }
+
+    #[test]
+    fn test_pow_rejects_target_longer_than_hash() {
+        let data = "hello world";
+        let difficulty = 1;
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+        let target = [0x30u8; 33];
+
+        let (hash, nonce) = pow.calculate_pow(&target[..1]);
+
+        assert!(pow.calculate_pow_from(&target, 0).is_err());
+        assert!(!pow.verify_pow(&target, (hash.clone(), nonce)));
+        assert!(!pow.verify_pow(&target[..1], (Vec::new(), nonce)));
+        assert!(pow.verify_pow(&target[..1], (hash, nonce)));
+    }
+
+    #[test]
+    fn test_pow_try_calculate_pow_rejects_target_longer_than_hash() {
+        let data = "hello world";
+        let difficulty = 1;
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+
+        assert!(pow.try_calculate_pow(&[0x30u8; 33]).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the 32-byte hash length")]
+    fn test_pow_calculate_pow_panics_on_target_longer_than_hash() {
+        let data = "hello world";
+        let difficulty = 1;
+        let algorithm = PoWAlgorithm::Sha2_256;
+        let pow = PoW::new(data, difficulty, algorithm).unwrap();
+
+        pow.calculate_pow(&[0x30u8; 33]);
     }
 }